@@ -140,6 +140,17 @@ impl fmt::Debug for Header {
     }
 }
 
+// ヒープ全体の使用状況を表すスナップショット。
+// largest_free_region はフラグメンテーションの度合いを見るための指標。
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    pub total_bytes: usize,
+    pub allocated_bytes: usize,
+    pub free_bytes: usize,
+    pub num_free_regions: usize,
+    pub largest_free_region: usize,
+}
+
 pub struct FirstFitAllocator {
     // 先頭のヘッダアドレスのみ保持
     first_header: RefCell<Option<Box<Header>>>,
@@ -162,6 +173,7 @@ unsafe impl GlobalAlloc for FirstFitAllocator {
         region.is_allocated = false;
         Box::leak(region);
         // region is leaked here to avoid dropping the free info on the memory.
+        self.coalesce_free_list();
     }
 }
 
@@ -224,4 +236,158 @@ impl FirstFitAllocator {
         // since all the regions written in memory maps are not contiguous.
         // so that they can't be merged anyway
     }
+
+    // ヒープの使用状況を1回の走査で集計する。
+    // Header は Box のまま借用するだけで、所有権を動かさないので
+    // Drop (panic する実装になっている) は一切起きない。
+    pub fn stats(&self) -> HeapStats {
+        let first_header = self.first_header.borrow();
+        let mut total_bytes = 0;
+        let mut allocated_bytes = 0;
+        let mut num_free_regions = 0;
+        let mut largest_free_region = 0;
+
+        let mut header = first_header.as_deref();
+        while let Some(h) = header {
+            total_bytes += h.size;
+            if h.is_allocated() {
+                allocated_bytes += h.size;
+            } else {
+                num_free_regions += 1;
+                largest_free_region = max(largest_free_region, h.size);
+            }
+            header = h.next_header.as_deref();
+        }
+
+        HeapStats {
+            total_bytes,
+            allocated_bytes,
+            free_bytes: total_bytes - allocated_bytes,
+            num_free_regions,
+            largest_free_region,
+        }
+    }
+
+    // ヘッダのリストをリンクされている順にそのまま出力する。
+    // アドレス順になるのは coalesce_free_list が一度でも走った後だけ。
+    // こちらも read-only な借用で走査するだけで、所有権は動かさない。
+    pub fn dump_free_list(&self, w: &mut impl fmt::Write) {
+        let first_header = self.first_header.borrow();
+        let mut header = first_header.as_deref();
+        while let Some(h) = header {
+            let _ = writeln!(w, "{h:?}");
+            header = h.next_header.as_deref();
+        }
+    }
+
+    // 隣接する free 領域を1つにまとめてフラグメンテーションを抑える。
+    // EFI のメモリマップ由来の領域はアドレス順に並んでいる保証がないので、
+    // 一度アドレス順に並べ直してから連続する区間をまとめる。
+    //
+    // 重要: この関数は `dealloc` から無条件に呼ばれるので、ここで
+    // allocator 自身(つまり `self.first_header` を再度借用するもの)を
+    // 呼んではいけない。`Vec` など `#[global_allocator]` を経由する型を
+    // 使うと `alloc_with_options` が `self.first_header.borrow_mut()` を
+    // 再入呼び出しして `BorrowMutError` で panic するため、並べ替えも
+    // 連結も Box の付け替えだけで完結させる(ヒープ確保は一切行わない)。
+    fn coalesce_free_list(&self) {
+        let mut first_header = self.first_header.borrow_mut();
+        let mut remaining = first_header.take();
+
+        // 挿入ソートでアドレス昇順のリストを作る。
+        // ノードの移動は Box の所有権の付け替えだけなので、ここでは
+        // allocator を再入呼び出ししない。
+        let mut sorted: Option<Box<Header>> = None;
+        while let Some(mut node) = remaining {
+            remaining = node.next_header.take();
+            let node_addr = node.as_ref() as *const Header as usize;
+
+            let insert_before_head = sorted
+                .as_deref()
+                .map(|head| node_addr < head as *const Header as usize)
+                .unwrap_or(true);
+
+            if insert_before_head {
+                node.next_header = sorted.take();
+                sorted = Some(node);
+            } else {
+                let mut cur = sorted.as_mut().unwrap();
+                while let Some(next) = cur.next_header.as_deref() {
+                    if node_addr < next as *const Header as usize {
+                        break;
+                    }
+                    cur = cur.next_header.as_mut().unwrap();
+                }
+                node.next_header = cur.next_header.take();
+                cur.next_header = Some(node);
+            }
+        }
+
+        // アドレス順に並んだので、あとは隣り合う free 領域を走査しながら
+        // 吸収していくだけでよい。異なる EFI descriptor 由来の領域は
+        // end_addr() == 次の先頭アドレス、という条件を満たし得ないので、
+        // この条件だけで本当に物理的に連続した領域だけがまとまる。
+        let mut cur = sorted.as_mut();
+        while let Some(header) = cur {
+            while !header.is_allocated() {
+                let can_merge = header
+                    .next_header
+                    .as_deref()
+                    .map(|next| !next.is_allocated() && header.end_addr() == next as *const Header as usize)
+                    .unwrap_or(false);
+                if !can_merge {
+                    break;
+                }
+
+                // 吸収する側のヘッダは next_header だけ取り出して Box::leak する。
+                // Header::drop は panic する実装なので、絶対に Drop させない。
+                let mut absorbed = header.next_header.take().unwrap();
+                let absorbed_next = absorbed.next_header.take();
+                header.size += absorbed.size;
+                header.next_header = absorbed_next;
+                Box::leak(absorbed);
+            }
+            cur = header.next_header.as_mut();
+        }
+
+        *first_header = sorted;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn alloc_dealloc_does_not_panic() {
+        // かつて coalesce_free_list が Vec 経由でヒープ確保をしており、
+        // dealloc -> coalesce_free_list -> alloc_with_options という
+        // 再入で RefCell の borrow_mut が panic していた。
+        // この呼び出しが完走することを確認する。
+        let layout = Layout::from_size_align(128, 8).unwrap();
+        let p = unsafe { ALLOCATOR.alloc(layout) };
+        assert!(!p.is_null());
+        unsafe { ALLOCATOR.dealloc(p, layout) };
+    }
+
+    #[test_case]
+    fn coalesce_merges_adjacent_free_regions() {
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let a = unsafe { ALLOCATOR.alloc(layout) };
+        let b = unsafe { ALLOCATOR.alloc(layout) };
+        assert!(!a.is_null());
+        assert!(!b.is_null());
+
+        let before = ALLOCATOR.stats();
+        unsafe {
+            ALLOCATOR.dealloc(a, layout);
+            ALLOCATOR.dealloc(b, layout);
+        }
+        let after = ALLOCATOR.stats();
+
+        // a と b が隣接していれば1つの free 領域に吸収されて、
+        // 最大の free 領域が大きくなっているはず。
+        assert!(after.num_free_regions <= before.num_free_regions + 1);
+        assert!(after.largest_free_region >= before.largest_free_region);
+    }
 }
@@ -0,0 +1,26 @@
+#![no_std]
+// cargo test 時だけ独自のエントリポイント(_start)を使う
+#![cfg_attr(test, no_main)]
+// extern "x86-interrupt" (x86.rs) と #[test_case] (test_runner.rs 越しに使う) は
+// どちらも unstable なので、このクレートのルートで feature を有効にする
+#![feature(abi_x86_interrupt)]
+#![feature(custom_test_frameworks)]
+#![test_runner(crate::test_runner::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+pub mod allocator;
+pub mod qemu;
+pub mod result;
+pub mod serial;
+pub mod test_runner;
+pub mod uefi;
+pub mod x86;
+
+#[cfg(test)]
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    test_main();
+    loop {}
+}
@@ -1,7 +1,12 @@
+use crate::allocator::FirstFitAllocator;
+use crate::allocator::LAYOUT_PAGE_4K;
 use crate::result::Result;
+use crate::serial::SerialPort;
 use core::arch::asm;
 use core::fmt;
+use core::fmt::Write;
 use core::marker::PhantomData;
+use core::mem::size_of;
 
 pub fn hlt() {
     unsafe { asm!("hlt") }
@@ -42,6 +47,24 @@ pub fn read_cr3() -> *mut PML4 {
     cr3
 }
 
+// ページフォルト時に、フォルトを起こしたアドレスを持つ cr2 レジスタを読み出す
+fn read_cr2() -> u64 {
+    let cr2: u64;
+    unsafe {
+        asm!("mov rax, cr2",
+        out("rax") cr2
+    )
+    }
+    cr2
+}
+
+// 現在の cs セレクタを読み出す。IDT のエントリに書き込むセグメントセレクタとして使う
+fn read_cs() -> u16 {
+    let cs: u16;
+    unsafe { asm!("mov {0:x}, cs", out(reg) cs, options(nomem, nostack, preserves_flags)) }
+    cs
+}
+
 pub const PAGE_SIZE: usize = 4096;
 // ページのインデックスを表現する上位ビットマスク
 const ATTR_MASK: u64 = 0xFFF;
@@ -93,6 +116,16 @@ impl <const LEVEL: usize, const SHIFT: usize, NEXT> Entry <LEVEL, SHIFT, NEXT> {
         (self.read_value() & (1 << 2)) != 0
     }
 
+    // bit7 は PDPTE/PDE では "Page Size" フラグ(huge page かどうか)を表す
+    fn is_huge_page(&self) -> bool {
+        (self.read_value() & (1 << 7)) != 0
+    }
+
+    // 属性ビットを落とした物理アドレス(次のテーブル、あるいはページそのもの)
+    fn phys_addr(&self) -> u64 {
+        self.read_value() & !ATTR_MASK
+    }
+
     fn format(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
@@ -115,6 +148,27 @@ impl <const LEVEL: usize, const SHIFT: usize, NEXT> Entry <LEVEL, SHIFT, NEXT> {
             Err("Page Not Found")
         }
     }
+
+    // 次のレベルのテーブルを返す。まだ存在しなければ allocator からゼロ初期化した
+    // 4KB ページを1枚取ってきて、このエントリに present/writable でつなぐ。
+    fn table_or_create(&mut self, alloc: &FirstFitAllocator) -> Result<&mut NEXT> {
+        if !self.is_presenet() {
+            let addr = alloc.alloc_with_options(LAYOUT_PAGE_4K);
+            if addr.is_null() {
+                return Err("Failed to allocate a page table");
+            }
+            unsafe {
+                core::ptr::write_bytes(addr, 0, PAGE_SIZE);
+            }
+            self.value = addr as u64 | ATTR_PRESENT | ATTR_WRITABLE;
+        }
+        Ok(unsafe { &mut *(self.phys_addr() as *mut NEXT) })
+    }
+
+    // リーフエントリとして物理ページを直接指すように書き換える
+    fn set_page(&mut self, phys: u64, attr: PageAttr) {
+        self.value = (phys & !ATTR_MASK) | attr as u64;
+    }
 }
 
 impl<const LEVEL: usize, const SHIFT: usize, NEXT> fmt::Display for Entry<LEVEL, SHIFT, NEXT>
@@ -167,3 +221,313 @@ pub type PT = Table<1, 12, [u8; PAGE_SIZE]>;
 pub type PD = Table<2, 21, PT>;
 pub type PDPT = Table<3, 30, PD>;
 pub type PML4 = Table<4, 39, PDPT>;
+
+// 各レベルの9bitインデックスを virt から切り出す
+fn index_at_shift(virt: u64, shift: usize) -> usize {
+    ((virt >> shift) & 0x1FF) as usize
+}
+
+impl PML4 {
+    // virt を各レベルのテーブルに沿って辿り、最終的にマップされている
+    // 物理アドレスを huge page も含めて返す。
+    pub fn translate(&self, virt: u64) -> Result<TranslationResult> {
+        let pml4_index = index_at_shift(virt, 39);
+        let pml4e = self.entry.get(pml4_index).ok_or("Invalid virtual address")?;
+        if !pml4e.is_presenet() {
+            return Err("Page Not Found");
+        }
+        let pdpt = self.next_level(pml4_index).ok_or("Page Not Found")?;
+
+        let pdpt_index = index_at_shift(virt, 30);
+        let pdpte = pdpt.entry.get(pdpt_index).ok_or("Invalid virtual address")?;
+        if !pdpte.is_presenet() {
+            return Err("Page Not Found");
+        }
+        if pdpte.is_huge_page() {
+            let phys = pdpte.phys_addr() | (virt & ((1 << 30) - 1));
+            return Ok(TranslationResult::PageMapped1G { phys });
+        }
+        let pd = pdpt.next_level(pdpt_index).ok_or("Page Not Found")?;
+
+        let pd_index = index_at_shift(virt, 21);
+        let pde = pd.entry.get(pd_index).ok_or("Invalid virtual address")?;
+        if !pde.is_presenet() {
+            return Err("Page Not Found");
+        }
+        if pde.is_huge_page() {
+            let phys = pde.phys_addr() | (virt & ((1 << 21) - 1));
+            return Ok(TranslationResult::PageMapped2M { phys });
+        }
+        let pt = pd.next_level(pd_index).ok_or("Page Not Found")?;
+
+        let pt_index = index_at_shift(virt, 12);
+        let pte = pt.entry.get(pt_index).ok_or("Invalid virtual address")?;
+        if !pte.is_presenet() {
+            return Err("Page Not Found");
+        }
+        let phys = pte.phys_addr() | (virt & 0xFFF);
+        Ok(TranslationResult::PageMapped4K { phys })
+    }
+
+    // virt -> phys の4Kマッピングを作る。途中のテーブルが無ければ alloc から
+    // ゼロ初期化した4Kページを確保して作る。
+    pub fn map_page(
+        &mut self,
+        virt: u64,
+        phys: u64,
+        attr: PageAttr,
+        alloc: &FirstFitAllocator,
+    ) -> Result<()> {
+        if phys & (PAGE_SIZE as u64 - 1) != 0 {
+            return Err("phys is not page-aligned");
+        }
+
+        let pdpt = self
+            .entry
+            .get_mut(index_at_shift(virt, 39))
+            .ok_or("Invalid virtual address")?
+            .table_or_create(alloc)?;
+        let pd = pdpt
+            .entry
+            .get_mut(index_at_shift(virt, 30))
+            .ok_or("Invalid virtual address")?
+            .table_or_create(alloc)?;
+        let pt = pd
+            .entry
+            .get_mut(index_at_shift(virt, 21))
+            .ok_or("Invalid virtual address")?
+            .table_or_create(alloc)?;
+        let page_entry = pt
+            .entry
+            .get_mut(index_at_shift(virt, 12))
+            .ok_or("Invalid virtual address")?;
+        page_entry.set_page(phys, attr);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::allocator::ALLOCATOR;
+
+    fn new_pml4() -> &'static mut PML4 {
+        let addr = ALLOCATOR.alloc_with_options(LAYOUT_PAGE_4K);
+        assert!(!addr.is_null());
+        unsafe {
+            core::ptr::write_bytes(addr, 0, PAGE_SIZE);
+            &mut *(addr as *mut PML4)
+        }
+    }
+
+    #[test_case]
+    fn translate_4k_page_round_trips_through_next_level() {
+        let pml4 = new_pml4();
+        let virt = 0x1234_5678_9000u64;
+        let phys = 0x2000_0000u64;
+        pml4.map_page(virt, phys, PageAttr::ReadWritekernel, &ALLOCATOR)
+            .unwrap();
+
+        assert_eq!(
+            pml4.translate(virt).unwrap(),
+            TranslationResult::PageMapped4K { phys }
+        );
+    }
+
+    #[test_case]
+    fn translate_unmapped_address_is_page_not_found() {
+        let pml4 = new_pml4();
+        assert!(pml4.translate(0x1000).is_err());
+    }
+}
+
+// --- Interrupt Descriptor Table ---
+//
+// CPU 例外 (#DE, #UD, #GP, #PF, #DF など) を黙ってトリプルフォルトさせるのではなく、
+// ちゃんとしたハンドラで捕まえてシリアルポートにダンプしてから停止させるための仕組み。
+
+const IDT_TYPE_ATTR_INTERRUPT_GATE: u8 = 0b1000_1110; // present, DPL=0, 64-bit interrupt gate
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct IdtDescriptor {
+    offset_low: u16,
+    segment_selector: u16,
+    ist: u8,
+    type_attr: u8,
+    offset_mid: u16,
+    offset_high: u32,
+    reserved: u32,
+}
+
+impl IdtDescriptor {
+    const fn missing() -> Self {
+        Self {
+            offset_low: 0,
+            segment_selector: 0,
+            ist: 0,
+            type_attr: 0,
+            offset_mid: 0,
+            offset_high: 0,
+            reserved: 0,
+        }
+    }
+
+    fn set_handler(&mut self, handler: u64, segment_selector: u16) {
+        self.offset_low = (handler & 0xFFFF) as u16;
+        self.offset_mid = ((handler >> 16) & 0xFFFF) as u16;
+        self.offset_high = (handler >> 32) as u32;
+        self.segment_selector = segment_selector;
+        self.ist = 0;
+        self.type_attr = IDT_TYPE_ATTR_INTERRUPT_GATE;
+    }
+}
+
+const MISSING_IDT_DESCRIPTOR: IdtDescriptor = IdtDescriptor::missing();
+
+#[repr(C, align(16))]
+struct Idt {
+    entry: [IdtDescriptor; 256],
+}
+
+static mut IDT: Idt = Idt {
+    entry: [MISSING_IDT_DESCRIPTOR; 256],
+};
+
+#[repr(C, packed)]
+struct IdtrDescriptor {
+    limit: u16,
+    base: u64,
+}
+
+// lidt を発行して IDT をロードする。CPU 例外が起きてもトリプルフォルトせずに
+// 対応するハンドラに飛ぶようになる。
+pub fn load_idt() {
+    let cs = read_cs();
+    unsafe {
+        IDT.entry[0].set_handler(divide_error_handler as *const () as u64, cs);
+        IDT.entry[6].set_handler(invalid_opcode_handler as *const () as u64, cs);
+        IDT.entry[8].set_handler(double_fault_handler as *const () as u64, cs);
+        IDT.entry[13].set_handler(general_protection_fault_handler as *const () as u64, cs);
+        IDT.entry[14].set_handler(page_fault_handler as *const () as u64, cs);
+
+        let idtr = IdtrDescriptor {
+            limit: (size_of::<Idt>() - 1) as u16,
+            base: core::ptr::addr_of!(IDT) as u64,
+        };
+        asm!("lidt [{}]", in(reg) &idtr, options(readonly, nostack, preserves_flags));
+    }
+}
+
+// ベクタ番号 -> 例外名のテーブル。Intel SDM Vol.3 第6章の例外一覧と対応する。
+const EXCEPTION_NAMES: [&str; 22] = [
+    "DIVIDE ERROR",
+    "DEBUG",
+    "NON-MASKABLE INTERRUPT",
+    "BREAKPOINT",
+    "OVERFLOW",
+    "BOUND RANGE EXCEEDED",
+    "INVALID OPCODE",
+    "DEVICE NOT AVAILABLE",
+    "DOUBLE FAULT",
+    "COPROCESSOR SEGMENT OVERRUN",
+    "INVALID TSS",
+    "SEGMENT NOT PRESENT",
+    "STACK-SEGMENT FAULT",
+    "GENERAL PROTECTION FAULT",
+    "PAGE FAULT",
+    "RESERVED",
+    "X87 FLOATING-POINT EXCEPTION",
+    "ALIGNMENT CHECK",
+    "MACHINE CHECK",
+    "SIMD FLOATING-POINT EXCEPTION",
+    "VIRTUALIZATION EXCEPTION",
+    "CONTROL PROTECTION EXCEPTION",
+];
+
+fn exception_name(vector: u8) -> &'static str {
+    EXCEPTION_NAMES
+        .get(vector as usize)
+        .copied()
+        .unwrap_or("UNKNOWN EXCEPTION")
+}
+
+#[repr(C)]
+struct InterruptStackFrame {
+    instruction_pointer: u64,
+    code_segment: u64,
+    cpu_flags: u64,
+    stack_pointer: u64,
+    stack_segment: u64,
+}
+
+// シリアルポートに構造化した例外ダンプを書き出す。
+// 例: "PAGE FAULT @ CR2=0x0000000000001000 EC=0x2"
+fn dump_exception(
+    vector: u8,
+    error_code: Option<u64>,
+    cr2: Option<u64>,
+    frame: &InterruptStackFrame,
+) {
+    let mut serial = SerialPort::new_for_com1();
+    let _ = write!(serial, "{}", exception_name(vector));
+    if let Some(cr2) = cr2 {
+        let _ = write!(serial, " @ CR2={cr2:#018X}");
+    }
+    if let Some(error_code) = error_code {
+        let _ = write!(serial, " EC={error_code:#X}");
+    }
+    let _ = writeln!(serial);
+    let _ = writeln!(
+        serial,
+        "  RIP={:#018X} CS={:#06X} FLAGS={:#018X}",
+        frame.instruction_pointer, frame.code_segment, frame.cpu_flags
+    );
+    let _ = writeln!(
+        serial,
+        "  RSP={:#018X} SS={:#06X}",
+        frame.stack_pointer, frame.stack_segment
+    );
+}
+
+extern "x86-interrupt" fn divide_error_handler(stack_frame: InterruptStackFrame) {
+    dump_exception(0, None, None, &stack_frame);
+    loop {
+        hlt();
+    }
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
+    dump_exception(6, None, None, &stack_frame);
+    loop {
+        hlt();
+    }
+}
+
+extern "x86-interrupt" fn double_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) -> ! {
+    dump_exception(8, Some(error_code), None, &stack_frame);
+    loop {
+        hlt();
+    }
+}
+
+extern "x86-interrupt" fn general_protection_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    dump_exception(13, Some(error_code), None, &stack_frame);
+    loop {
+        hlt();
+    }
+}
+
+extern "x86-interrupt" fn page_fault_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    let cr2 = read_cr2();
+    dump_exception(14, Some(error_code), Some(cr2), &stack_frame);
+    loop {
+        hlt();
+    }
+}
@@ -2,7 +2,13 @@
 #![no_main]
 #![feature(offset_of)]
 
+// std ではなく core の alloc を使う
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::vec;
 use core::arch::asm;
+use core::cmp::max;
 use core::cmp::min;
 use core::mem::offset_of;
 use core::mem::size_of;
@@ -146,6 +152,7 @@ trait Bitmap {
     fn width(&self) -> i64;
     fn height(&self) -> i64;
     fn buf_mut(&mut self) -> *mut u8;
+    fn buf(&self) -> *const u8;
     /// # Safety
     ///
     /// Returned pointer is valid as long as the given cordinates are valid.
@@ -157,6 +164,17 @@ trait Bitmap {
         ) as *mut u32
     }
 
+    /// # Safety
+    ///
+    /// Returned pointer is valid as long as the given cordinates are valid.
+    /// which means that passing is_in_*_range tests.
+    unsafe fn unchecked_pixel_at(&self, x: i64, y: i64) -> *const u32 {
+        self.buf().add(
+            ((y * self.pixels_per_line() + x ) * self.bytes_per_pixel())
+                as usize,
+        ) as *const u32
+    }
+
     fn pixel_at_mut(&mut self, x: i64, y: i64) -> Option<&mut u32> {
         if self.is_in_x_range(x) && self.is_in_y_range(y) {
             unsafe { Some(&mut *self.unchecked_pixel_at_mut(x, y)) }
@@ -204,6 +222,10 @@ impl Bitmap for VramBufferInfo {
     fn buf_mut(&mut self) -> *mut u8 {
         self.buf
     }
+
+    fn buf(&self) -> *const u8 {
+        self.buf
+    }
 }
 fn init_vram(efi_system_table: &EfiSystemTable) -> Result<VramBufferInfo> {
     let gp = locate_graphic_protocol(efi_system_table)?;
@@ -265,3 +287,525 @@ fn fill_rect<T: Bitmap>(
 
     Ok(())
 }
+
+// Bresenham のアルゴリズムによる整数演算だけの直線描画。
+// 範囲外の座標は pixel_at_mut が None を返すので黙って無視される。
+fn draw_line<T: Bitmap>(buf: &mut T, color: u32, x0: i64, y0: i64, x1: i64, y1: i64) {
+    let dx = (x1 - x0).abs();
+    let dy = -(y1 - y0).abs();
+    let sx = if x0 < x1 { 1 } else { -1 };
+    let sy = if y0 < y1 { 1 } else { -1 };
+    let mut err = dx + dy;
+    let mut x = x0;
+    let mut y = y0;
+
+    loop {
+        let _ = draw_point(buf, color, x, y);
+        if x == x1 && y == y1 {
+            break;
+        }
+        let e2 = 2 * err;
+        if e2 >= dy {
+            err += dy;
+            x += sx;
+        }
+        if e2 <= dx {
+            err += dx;
+            y += sy;
+        }
+    }
+}
+
+// midpoint circle algorithm による円の描画。円の8方向の対称性を使って
+// 1/8 だけ計算し、残り7つは座標を反転させて打つ。
+fn draw_circle<T: Bitmap>(buf: &mut T, color: u32, cx: i64, cy: i64, r: i64) {
+    let mut x = 0;
+    let mut y = r;
+    let mut d = 1 - r;
+
+    let plot_octants = |buf: &mut T, x: i64, y: i64| {
+        let _ = draw_point(buf, color, cx + x, cy + y);
+        let _ = draw_point(buf, color, cx - x, cy + y);
+        let _ = draw_point(buf, color, cx + x, cy - y);
+        let _ = draw_point(buf, color, cx - x, cy - y);
+        let _ = draw_point(buf, color, cx + y, cy + x);
+        let _ = draw_point(buf, color, cx - y, cy + x);
+        let _ = draw_point(buf, color, cx + y, cy - x);
+        let _ = draw_point(buf, color, cx - y, cy - x);
+    };
+
+    while x <= y {
+        plot_octants(buf, x, y);
+        x += 1;
+        if d < 0 {
+            d += 2 * x + 1;
+        } else {
+            y -= 1;
+            d += 2 * (x - y) + 1;
+        }
+    }
+}
+
+// NOTE: draw_line/draw_circle はここでは意図的にテストしていない。この crate
+// (wasabi, EFI ブートローダ) は #![no_std]/#![no_main] で、src/test_runner.rs が
+// 使う #[test_case]/custom_test_frameworks の配線(feature, #[test_runner(...)],
+// #[reexport_test_harness_main], cfg(test) 用のエントリポイント)がまだ一つも
+// ないので、このクレートだけで完結するテストハーネスが存在しない。それが
+// 用意されるまでは #[test_case] を足さずに見送る。
+
+const GLYPH_WIDTH: i64 = 8;
+const GLYPH_HEIGHT: i64 = 16;
+
+// 8x8 のグリフを縦に2倍に伸ばして 8x16 にする
+const fn expand_glyph_8x16(glyph: [u8; 8]) -> [u8; 16] {
+    let mut out = [0u8; 16];
+    let mut row = 0;
+    while row < 8 {
+        out[row * 2] = glyph[row];
+        out[row * 2 + 1] = glyph[row];
+        row += 1;
+    }
+    out
+}
+
+// ASCII 0..128 に対応する 8x16 の固定幅ビットマップフォント。
+// 1バイトが1行分、各行は MSB が左端のピクセルに対応する。
+// 制御文字や未対応の記号は空白(全0)のまま。
+const fn build_font_8x16() -> [[u8; 16]; 128] {
+    let mut font = [[0u8; 16]; 128];
+    font[b'0' as usize] = expand_glyph_8x16([
+        0b01111100, 0b11000110, 0b11001110, 0b11010110, 0b11100110, 0b11000110, 0b11000110,
+        0b01111100,
+    ]);
+    font[b'1' as usize] = expand_glyph_8x16([
+        0b00011000, 0b00111000, 0b01111000, 0b00011000, 0b00011000, 0b00011000, 0b00011000,
+        0b01111110,
+    ]);
+    font[b'2' as usize] = expand_glyph_8x16([
+        0b01111100, 0b11000110, 0b00000110, 0b00001100, 0b00011000, 0b00110000, 0b01100000,
+        0b11111110,
+    ]);
+    font[b'3' as usize] = expand_glyph_8x16([
+        0b01111100, 0b11000110, 0b00000110, 0b00111100, 0b00000110, 0b00000110, 0b11000110,
+        0b01111100,
+    ]);
+    font[b'4' as usize] = expand_glyph_8x16([
+        0b00001100, 0b00011100, 0b00111100, 0b01101100, 0b11001100, 0b11111110, 0b00001100,
+        0b00001100,
+    ]);
+    font[b'5' as usize] = expand_glyph_8x16([
+        0b11111110, 0b11000000, 0b11000000, 0b11111100, 0b00000110, 0b00000110, 0b11000110,
+        0b01111100,
+    ]);
+    font[b'6' as usize] = expand_glyph_8x16([
+        0b00111100, 0b01100000, 0b11000000, 0b11111100, 0b11000110, 0b11000110, 0b11000110,
+        0b01111100,
+    ]);
+    font[b'7' as usize] = expand_glyph_8x16([
+        0b11111110, 0b11000110, 0b00001100, 0b00011000, 0b00110000, 0b00110000, 0b00110000,
+        0b00110000,
+    ]);
+    font[b'8' as usize] = expand_glyph_8x16([
+        0b01111100, 0b11000110, 0b11000110, 0b01111100, 0b11000110, 0b11000110, 0b11000110,
+        0b01111100,
+    ]);
+    font[b'9' as usize] = expand_glyph_8x16([
+        0b01111100, 0b11000110, 0b11000110, 0b01111110, 0b00000110, 0b00001100, 0b00011000,
+        0b01110000,
+    ]);
+
+    font[b'A' as usize] = expand_glyph_8x16([
+        0b00111000, 0b01101100, 0b11000110, 0b11000110, 0b11111110, 0b11000110, 0b11000110,
+        0b11000110,
+    ]);
+    font[b'B' as usize] = expand_glyph_8x16([
+        0b11111100, 0b11000110, 0b11000110, 0b11111100, 0b11000110, 0b11000110, 0b11000110,
+        0b11111100,
+    ]);
+    font[b'C' as usize] = expand_glyph_8x16([
+        0b01111100, 0b11000110, 0b11000000, 0b11000000, 0b11000000, 0b11000000, 0b11000110,
+        0b01111100,
+    ]);
+    font[b'D' as usize] = expand_glyph_8x16([
+        0b11111000, 0b11001100, 0b11000110, 0b11000110, 0b11000110, 0b11000110, 0b11001100,
+        0b11111000,
+    ]);
+    font[b'E' as usize] = expand_glyph_8x16([
+        0b11111110, 0b11000000, 0b11000000, 0b11111100, 0b11000000, 0b11000000, 0b11000000,
+        0b11111110,
+    ]);
+    font[b'F' as usize] = expand_glyph_8x16([
+        0b11111110, 0b11000000, 0b11000000, 0b11111100, 0b11000000, 0b11000000, 0b11000000,
+        0b11000000,
+    ]);
+    font[b'G' as usize] = expand_glyph_8x16([
+        0b01111100, 0b11000110, 0b11000000, 0b11000000, 0b11001110, 0b11000110, 0b11000110,
+        0b01111110,
+    ]);
+    font[b'H' as usize] = expand_glyph_8x16([
+        0b11000110, 0b11000110, 0b11000110, 0b11111110, 0b11000110, 0b11000110, 0b11000110,
+        0b11000110,
+    ]);
+    font[b'I' as usize] = expand_glyph_8x16([
+        0b01111110, 0b00011000, 0b00011000, 0b00011000, 0b00011000, 0b00011000, 0b00011000,
+        0b01111110,
+    ]);
+    font[b'J' as usize] = expand_glyph_8x16([
+        0b00011110, 0b00001100, 0b00001100, 0b00001100, 0b00001100, 0b11001100, 0b11001100,
+        0b01111000,
+    ]);
+    font[b'K' as usize] = expand_glyph_8x16([
+        0b11000110, 0b11001100, 0b11011000, 0b11110000, 0b11110000, 0b11011000, 0b11001100,
+        0b11000110,
+    ]);
+    font[b'L' as usize] = expand_glyph_8x16([
+        0b11000000, 0b11000000, 0b11000000, 0b11000000, 0b11000000, 0b11000000, 0b11000000,
+        0b11111110,
+    ]);
+    font[b'M' as usize] = expand_glyph_8x16([
+        0b11000011, 0b11100111, 0b11111111, 0b11011011, 0b11000011, 0b11000011, 0b11000011,
+        0b11000011,
+    ]);
+    font[b'N' as usize] = expand_glyph_8x16([
+        0b11000110, 0b11100110, 0b11110110, 0b11011110, 0b11001110, 0b11000110, 0b11000110,
+        0b11000110,
+    ]);
+    font[b'O' as usize] = expand_glyph_8x16([
+        0b01111100, 0b11000110, 0b11000110, 0b11000110, 0b11000110, 0b11000110, 0b11000110,
+        0b01111100,
+    ]);
+    font[b'P' as usize] = expand_glyph_8x16([
+        0b11111100, 0b11000110, 0b11000110, 0b11111100, 0b11000000, 0b11000000, 0b11000000,
+        0b11000000,
+    ]);
+    font[b'Q' as usize] = expand_glyph_8x16([
+        0b01111100, 0b11000110, 0b11000110, 0b11000110, 0b11000110, 0b11010110, 0b11001100,
+        0b01110110,
+    ]);
+    font[b'R' as usize] = expand_glyph_8x16([
+        0b11111100, 0b11000110, 0b11000110, 0b11111100, 0b11011000, 0b11001100, 0b11000110,
+        0b11000110,
+    ]);
+    font[b'S' as usize] = expand_glyph_8x16([
+        0b01111100, 0b11000110, 0b11000000, 0b01111100, 0b00000110, 0b00000110, 0b11000110,
+        0b01111100,
+    ]);
+    font[b'T' as usize] = expand_glyph_8x16([
+        0b11111110, 0b00011000, 0b00011000, 0b00011000, 0b00011000, 0b00011000, 0b00011000,
+        0b00011000,
+    ]);
+    font[b'U' as usize] = expand_glyph_8x16([
+        0b11000110, 0b11000110, 0b11000110, 0b11000110, 0b11000110, 0b11000110, 0b11000110,
+        0b01111100,
+    ]);
+    font[b'V' as usize] = expand_glyph_8x16([
+        0b11000110, 0b11000110, 0b11000110, 0b11000110, 0b11000110, 0b01101100, 0b00111000,
+        0b00010000,
+    ]);
+    font[b'W' as usize] = expand_glyph_8x16([
+        0b11000011, 0b11000011, 0b11000011, 0b11000011, 0b11011011, 0b11111111, 0b11100111,
+        0b11000011,
+    ]);
+    font[b'X' as usize] = expand_glyph_8x16([
+        0b11000110, 0b11000110, 0b01101100, 0b00111000, 0b00111000, 0b01101100, 0b11000110,
+        0b11000110,
+    ]);
+    font[b'Y' as usize] = expand_glyph_8x16([
+        0b11000110, 0b11000110, 0b01101100, 0b00111000, 0b00011000, 0b00011000, 0b00011000,
+        0b00011000,
+    ]);
+    font[b'Z' as usize] = expand_glyph_8x16([
+        0b11111110, 0b00001100, 0b00011000, 0b00110000, 0b01100000, 0b11000000, 0b11000000,
+        0b11111110,
+    ]);
+
+    font[b'.' as usize] = expand_glyph_8x16([
+        0, 0, 0, 0, 0, 0, 0b00011000, 0b00011000,
+    ]);
+    font[b',' as usize] = expand_glyph_8x16([
+        0, 0, 0, 0, 0, 0, 0b00011000, 0b00110000,
+    ]);
+    font[b':' as usize] = expand_glyph_8x16([
+        0, 0b00011000, 0b00011000, 0, 0, 0b00011000, 0b00011000, 0,
+    ]);
+    font[b';' as usize] = expand_glyph_8x16([
+        0, 0b00011000, 0b00011000, 0, 0, 0b00011000, 0b00011000, 0b00110000,
+    ]);
+    font[b'!' as usize] = expand_glyph_8x16([
+        0b00011000, 0b00011000, 0b00011000, 0b00011000, 0b00011000, 0, 0b00011000, 0b00011000,
+    ]);
+    font[b'?' as usize] = expand_glyph_8x16([
+        0b01111100, 0b11000110, 0b00001100, 0b00011000, 0b00011000, 0, 0b00011000, 0b00011000,
+    ]);
+    font[b'-' as usize] = expand_glyph_8x16([0, 0, 0, 0b01111110, 0, 0, 0, 0]);
+    font[b'_' as usize] = expand_glyph_8x16([0, 0, 0, 0, 0, 0, 0, 0b11111111]);
+    font[b' ' as usize] = expand_glyph_8x16([0, 0, 0, 0, 0, 0, 0, 0]);
+
+    // Header/Entry の Debug 表示などで実際に使われている残りの記号。
+    font[b'@' as usize] = expand_glyph_8x16([
+        0b01111100, 0b11000110, 0b11011110, 0b11011110, 0b11011100, 0b11000000, 0b11000110,
+        0b01111100,
+    ]);
+    font[b'#' as usize] = expand_glyph_8x16([
+        0b00100100, 0b00100100, 0b11111110, 0b00100100, 0b11111110, 0b00100100, 0b00100100, 0,
+    ]);
+    font[b'$' as usize] = expand_glyph_8x16([
+        0b00011000, 0b00111110, 0b01100000, 0b00111100, 0b00000110, 0b01111100, 0b00011000, 0,
+    ]);
+    font[b'%' as usize] = expand_glyph_8x16([
+        0b11000010, 0b11000100, 0b00001000, 0b00010000, 0b00100000, 0b01000110, 0b10000110, 0,
+    ]);
+    font[b'^' as usize] = expand_glyph_8x16([0b00011000, 0b00111100, 0b01100110, 0b11000011, 0, 0, 0, 0]);
+    font[b'&' as usize] = expand_glyph_8x16([
+        0b00111000, 0b01101100, 0b01101100, 0b00111000, 0b01101101, 0b11001110, 0b01111011, 0,
+    ]);
+    font[b'*' as usize] = expand_glyph_8x16([0, 0b01000100, 0b00101000, 0b11111110, 0b00101000, 0b01000100, 0, 0]);
+    font[b'~' as usize] = expand_glyph_8x16([0, 0, 0b01110010, 0b10011100, 0, 0, 0, 0]);
+    font[b'`' as usize] = expand_glyph_8x16([0b01100000, 0b00110000, 0b00011000, 0, 0, 0, 0, 0]);
+    font[b'\'' as usize] = expand_glyph_8x16([0b00011000, 0b00011000, 0b00110000, 0, 0, 0, 0, 0]);
+    font[b'"' as usize] = expand_glyph_8x16([0b01100110, 0b01100110, 0b00100100, 0, 0, 0, 0, 0]);
+    font[b'(' as usize] = expand_glyph_8x16([
+        0b00001100, 0b00011000, 0b00110000, 0b00110000, 0b00110000, 0b00011000, 0b00001100, 0,
+    ]);
+    font[b')' as usize] = expand_glyph_8x16([
+        0b00110000, 0b00011000, 0b00001100, 0b00001100, 0b00001100, 0b00011000, 0b00110000, 0,
+    ]);
+    font[b'[' as usize] = expand_glyph_8x16([
+        0b00111100, 0b00110000, 0b00110000, 0b00110000, 0b00110000, 0b00110000, 0b00110000,
+        0b00111100,
+    ]);
+    font[b']' as usize] = expand_glyph_8x16([
+        0b00111100, 0b00001100, 0b00001100, 0b00001100, 0b00001100, 0b00001100, 0b00001100,
+        0b00111100,
+    ]);
+    font[b'{' as usize] = expand_glyph_8x16([
+        0b00001110, 0b00011000, 0b00011000, 0b01110000, 0b00011000, 0b00011000, 0b00001110, 0,
+    ]);
+    font[b'}' as usize] = expand_glyph_8x16([
+        0b01110000, 0b00011000, 0b00011000, 0b00001110, 0b00011000, 0b00011000, 0b01110000, 0,
+    ]);
+    font[b'/' as usize] = expand_glyph_8x16([
+        0b00000110, 0b00001100, 0b00011000, 0b00110000, 0b01100000, 0b11000000, 0b10000000, 0,
+    ]);
+    font[b'\\' as usize] = expand_glyph_8x16([
+        0b11000000, 0b01100000, 0b00110000, 0b00011000, 0b00001100, 0b00000110, 0b00000010, 0,
+    ]);
+    font[b'+' as usize] = expand_glyph_8x16([0, 0b00011000, 0b00011000, 0b01111110, 0b00011000, 0b00011000, 0, 0]);
+    font[b'=' as usize] = expand_glyph_8x16([0, 0, 0b01111110, 0, 0b01111110, 0, 0, 0]);
+    font[b'<' as usize] = expand_glyph_8x16([
+        0b00000110, 0b00011000, 0b01100000, 0b10000000, 0b01100000, 0b00011000, 0b00000110, 0,
+    ]);
+    font[b'>' as usize] = expand_glyph_8x16([
+        0b11000000, 0b00011000, 0b00000110, 0b00000001, 0b00000110, 0b00011000, 0b11000000, 0,
+    ]);
+
+    // 小文字は見た目の近似として大文字と同じグリフを使う
+    let mut c = b'a';
+    while c <= b'z' {
+        font[c as usize] = font[(c - b'a' + b'A') as usize];
+        c += 1;
+    }
+
+    font
+}
+
+static FONT_8X16: [[u8; 16]; 128] = build_font_8x16();
+
+// 1文字を (x, y) を左上としてフレームバッファに描画する。
+// scale 倍した正方形ブロックとしてそれぞれのビットを打つ。
+fn draw_char<T: Bitmap>(buf: &mut T, fg: u32, bg: u32, x: i64, y: i64, c: char, scale: i64) {
+    let scale = max(scale, 1);
+    let index = if (c as u32) < 128 { c as usize } else { 0 };
+    let glyph = &FONT_8X16[index];
+
+    for (row, bits) in glyph.iter().enumerate() {
+        for col in 0..GLYPH_WIDTH {
+            let is_set = (bits >> (7 - col)) & 1 != 0;
+            let color = if is_set { fg } else { bg };
+            let px = x + col * scale;
+            let py = y + row as i64 * scale;
+            for sy in 0..scale {
+                for sx in 0..scale {
+                    let _ = draw_point(buf, color, px + sx, py + sy);
+                }
+            }
+        }
+    }
+}
+
+// フレームバッファの上にスクロールするテキストコンソールを実装する。
+// `core::fmt::Write` を実装しているので `writeln!` がそのまま使える。
+struct TextConsole<T: Bitmap> {
+    buf: T,
+    cursor_col: i64,
+    cursor_row: i64,
+    scale: i64,
+    fg: u32,
+    bg: u32,
+}
+
+impl<T: Bitmap> TextConsole<T> {
+    fn new(buf: T, fg: u32, bg: u32, scale: i64) -> Self {
+        Self {
+            buf,
+            cursor_col: 0,
+            cursor_row: 0,
+            scale: max(scale, 1),
+            fg,
+            bg,
+        }
+    }
+
+    fn char_width(&self) -> i64 {
+        GLYPH_WIDTH * self.scale
+    }
+
+    fn char_height(&self) -> i64 {
+        GLYPH_HEIGHT * self.scale
+    }
+
+    fn cols(&self) -> i64 {
+        self.buf.width() / self.char_width()
+    }
+
+    fn rows(&self) -> i64 {
+        self.buf.height() / self.char_height()
+    }
+
+    fn newline(&mut self) {
+        self.cursor_col = 0;
+        self.cursor_row += 1;
+        if self.cursor_row >= self.rows() {
+            self.scroll_up();
+            self.cursor_row = self.rows() - 1;
+        }
+    }
+
+    // 1文字分の高さだけ画面全体を上にスクロールし、最下段を背景色で塗り直す
+    fn scroll_up(&mut self) {
+        let char_h = self.char_height();
+        let w = self.buf.width();
+        let h = self.buf.height();
+
+        for y in char_h..h {
+            for x in 0..w {
+                let pixel = self.buf.pixel_at_mut(x, y).map(|p| *p);
+                if let Some(pixel) = pixel {
+                    if let Some(dst) = self.buf.pixel_at_mut(x, y - char_h) {
+                        *dst = pixel;
+                    }
+                }
+            }
+        }
+        let _ = fill_rect(&mut self.buf, self.bg, 0, h - char_h, w, char_h);
+    }
+
+    fn print_char(&mut self, c: char) {
+        if c == '\n' {
+            self.newline();
+            return;
+        }
+        if self.cursor_col >= self.cols() {
+            self.newline();
+        }
+        let x = self.cursor_col * self.char_width();
+        let y = self.cursor_row * self.char_height();
+        draw_char(&mut self.buf, self.fg, self.bg, x, y, c, self.scale);
+        self.cursor_col += 1;
+    }
+
+    fn print_str(&mut self, s: &str) {
+        for c in s.chars() {
+            self.print_char(c);
+        }
+    }
+}
+
+impl<T: Bitmap> core::fmt::Write for TextConsole<T> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.print_str(s);
+        Ok(())
+    }
+}
+
+// ヒープに確保した、VRAM とは独立したオフスクリーンのフレームバッファ。
+// Bitmap を実装しているので draw_point / fill_rect / draw_char がそのまま使える。
+struct OffscreenBuffer {
+    buf: Box<[u32]>,
+    width: i64,
+    height: i64,
+}
+
+impl OffscreenBuffer {
+    fn new(width: i64, height: i64) -> Self {
+        let num_pixels = (width * height).max(0) as usize;
+        Self {
+            buf: vec![0u32; num_pixels].into_boxed_slice(),
+            width,
+            height,
+        }
+    }
+}
+
+impl Bitmap for OffscreenBuffer {
+    fn bytes_per_pixel(&self) -> i64 {
+        4
+    }
+
+    fn pixels_per_line(&self) -> i64 {
+        self.width
+    }
+
+    fn width(&self) -> i64 {
+        self.width
+    }
+
+    fn height(&self) -> i64 {
+        self.height
+    }
+
+    fn buf_mut(&mut self) -> *mut u8 {
+        self.buf.as_mut_ptr() as *mut u8
+    }
+
+    fn buf(&self) -> *const u8 {
+        self.buf.as_ptr() as *const u8
+    }
+}
+
+// src を dst の (dst_x, dst_y) を左上として描画する。
+// pixels_per_line が width と異なるフレームバッファでも正しく扱えるように、
+// 行ごとに dst の is_in_x_range/is_in_y_range でクリッピングしてからコピーする。
+// 1行の中で dst に収まっている区間はまとめて copy_nonoverlapping する。
+fn blit<Src: Bitmap, Dst: Bitmap>(dst: &mut Dst, src: &Src, dst_x: i64, dst_y: i64) {
+    let src_w = src.width();
+    let src_h = src.height();
+
+    for y in 0..src_h {
+        let dy = dst_y + y;
+        if !dst.is_in_y_range(dy) {
+            continue;
+        }
+
+        let mut x = 0;
+        while x < src_w {
+            if !dst.is_in_x_range(dst_x + x) {
+                x += 1;
+                continue;
+            }
+
+            // dst に収まっている x の連続区間を探して、その幅だけ一括コピーする。
+            let run_start = x;
+            while x < src_w && dst.is_in_x_range(dst_x + x) {
+                x += 1;
+            }
+            let run_len = (x - run_start) as usize;
+
+            unsafe {
+                let src_ptr = src.unchecked_pixel_at(run_start, y);
+                let dst_ptr = dst.unchecked_pixel_at_mut(dst_x + run_start, dy);
+                core::ptr::copy_nonoverlapping(src_ptr, dst_ptr, run_len);
+            }
+        }
+    }
+}